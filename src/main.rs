@@ -1,36 +1,356 @@
 use axum::{
-    extract::{Path, State},
+    error_handling::HandleErrorLayer,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
     http::StatusCode,
-    response::{Html, Json},
+    response::{Html, IntoResponse, Json, Redirect},
     routing::{get, post},
     Router,
 };
+use axum_oidc::{
+    error::MiddlewareError, EmptyAdditionalClaims, OidcAuthLayer, OidcClaims, OidcClient,
+    OidcLoginLayer,
+};
 use axum_server::tls_rustls::RustlsConfig;
+use futures_util::{SinkExt, StreamExt};
 use minesweeper::{Minesweeper, TileValue};
+use qrcode::{render::svg, QrCode};
 use rand::Rng;
+use sailfish::TemplateOnce;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
+    path::PathBuf,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
+use tokio::sync::broadcast;
+use tower::ServiceBuilder;
 use tower_http::{cors::CorsLayer, services::ServeDir};
 use axum::http::{Method, HeaderValue};
 use axum::http::header::CONTENT_TYPE;
+use tower_sessions::{MemoryStore, SessionManagerLayer};
+
+type GameStorage = Arc<dyn GameStore>;
 
-type GameStorage = Arc<Mutex<HashMap<String, GameInfo>>>;
+// Capacity of the per-game broadcast channel. Spectators that fall this far
+// behind just miss the intermediate frames and pick up the latest board.
+const BOARD_UPDATES_CAPACITY: usize = 16;
 
-#[derive(Debug)]
+// `Minesweeper` derives `Clone` (it already derives `Debug`, which `GameInfo`
+// relies on below), so `GameInfo` can too - that's what lets `GameStore::get`
+// and `GameStore::list` hand out snapshots instead of references.
+#[derive(Debug, Clone)]
 struct GameInfo {
     game: Option<Minesweeper>,
     size: usize,
     mine_count: usize,
     first_click_made: bool,
+    updates: broadcast::Sender<ActionResponse>,
+    players: Vec<Player>,
+    visibility: Visibility,
+    // Required of `join_game` callers for private games so knowing the
+    // game_id alone isn't enough to join; public games are discoverable via
+    // the lobby anyway, so they don't need one.
+    invite_code: Option<String>,
+    // Recorded for the replay token, but the installed `minesweeper` crate
+    // has no seeded constructor - mine placement isn't reproducible from
+    // this, only the first click and action log are actually replayed.
+    seed: u64,
+    first_click: Option<(usize, usize)>,
+    actions: Vec<(ActionKind, usize, usize)>,
+    owner: Option<String>,
+    last_touched: Instant,
+}
+
+/// Storage backend for games. The in-memory map is the default; a
+/// `FileGameStore` persists the replayable parts of each game (seed, first
+/// click, action log) to disk so the server can recover them on restart.
+///
+/// Reads and writes go through separate entry points (`with_games` vs.
+/// `with_games_mut`) so a backend only needs to persist on the latter -
+/// `get`/`list` are read-only and must never trigger a disk write.
+trait GameStore: Send + Sync {
+    fn with_games(&self, f: Box<dyn FnOnce(&HashMap<String, GameInfo>) + '_>);
+
+    fn with_games_mut(&self, f: Box<dyn FnOnce(&mut HashMap<String, GameInfo>) + '_>);
+
+    fn insert(&self, game_id: String, game_info: GameInfo) {
+        self.with_games_mut(Box::new(move |games| {
+            games.insert(game_id, game_info);
+        }));
+    }
+
+    fn get(&self, game_id: &str) -> Option<GameInfo> {
+        let mut found = None;
+        self.with_games(Box::new(|games| {
+            found = games.get(game_id).cloned();
+        }));
+        found
+    }
+
+    /// Mutates the game in place if it exists, touching `last_touched`.
+    /// Returns `false` if `game_id` isn't present.
+    fn update(&self, game_id: &str, f: Box<dyn FnOnce(&mut GameInfo) + '_>) -> bool {
+        let mut found = false;
+        self.with_games_mut(Box::new(move |games| {
+            if let Some(game_info) = games.get_mut(game_id) {
+                f(game_info);
+                game_info.last_touched = Instant::now();
+                found = true;
+            }
+        }));
+        found
+    }
+
+    fn list(&self) -> Vec<(String, GameInfo)> {
+        let mut entries = Vec::new();
+        self.with_games(Box::new(|games| {
+            entries = games.iter().map(|(id, info)| (id.clone(), info.clone())).collect();
+        }));
+        entries
+    }
+
+    /// Drops any game that hasn't been touched in over `ttl`.
+    fn evict_idle(&self, ttl: Duration) {
+        let now = Instant::now();
+        self.with_games_mut(Box::new(move |games| {
+            games.retain(|_, info| now.duration_since(info.last_touched) <= ttl);
+        }));
+    }
+}
+
+#[derive(Default)]
+struct InMemoryGameStore {
+    games: Mutex<HashMap<String, GameInfo>>,
+}
+
+impl GameStore for InMemoryGameStore {
+    fn with_games(&self, f: Box<dyn FnOnce(&HashMap<String, GameInfo>) + '_>) {
+        let games = self.games.lock().unwrap();
+        f(&games);
+    }
+
+    fn with_games_mut(&self, f: Box<dyn FnOnce(&mut HashMap<String, GameInfo>) + '_>) {
+        let mut games = self.games.lock().unwrap();
+        f(&mut games);
+    }
+}
+
+/// File-backed store. Only the replayable facts about a game (size, seed,
+/// first click, action log, players, owner) are written to disk; on load
+/// each game's board is reconstructed by starting a fresh game from the
+/// same first click and replaying its action log, and it gets a fresh
+/// broadcast channel since there are no subscribers to preserve across a
+/// restart. Note the installed `minesweeper` crate has no seeded
+/// constructor, so mine placement itself isn't reproduced - only the
+/// sequence of moves is.
+struct FileGameStore {
+    games: Mutex<HashMap<String, GameInfo>>,
+    path: PathBuf,
+}
+
+impl FileGameStore {
+    fn new(path: PathBuf) -> Self {
+        let games = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|data| serde_json::from_str::<HashMap<String, PersistedGame>>(&data).ok())
+            .map(|persisted| {
+                persisted
+                    .into_iter()
+                    .map(|(id, saved)| (id, saved.into_game_info()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            games: Mutex::new(games),
+            path,
+        }
+    }
+
+    /// Serializes and writes the snapshot on a blocking-pool thread so a
+    /// game mutation doesn't stall a tokio worker for the duration of the
+    /// disk write.
+    fn persist(&self, games: &HashMap<String, GameInfo>) {
+        let persisted: HashMap<String, PersistedGame> = games
+            .iter()
+            .map(|(id, info)| (id.clone(), PersistedGame::from(info)))
+            .collect();
+        let path = self.path.clone();
+
+        tokio::task::spawn_blocking(move || match serde_json::to_string(&persisted) {
+            Ok(data) => {
+                if let Err(err) = std::fs::write(&path, data) {
+                    eprintln!("failed to persist games to {}: {err}", path.display());
+                }
+            }
+            Err(err) => eprintln!("failed to serialize games for persistence: {err}"),
+        });
+    }
+}
+
+impl GameStore for FileGameStore {
+    fn with_games(&self, f: Box<dyn FnOnce(&HashMap<String, GameInfo>) + '_>) {
+        let games = self.games.lock().unwrap();
+        f(&games);
+    }
+
+    fn with_games_mut(&self, f: Box<dyn FnOnce(&mut HashMap<String, GameInfo>) + '_>) {
+        let mut games = self.games.lock().unwrap();
+        f(&mut games);
+        self.persist(&games);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedGame {
+    size: usize,
+    mine_count: usize,
+    visibility: Visibility,
+    invite_code: Option<String>,
+    seed: u64,
+    first_click: Option<(usize, usize)>,
+    actions: Vec<(ActionKind, usize, usize)>,
+    players: Vec<Player>,
+    owner: Option<String>,
+}
+
+impl From<&GameInfo> for PersistedGame {
+    fn from(game_info: &GameInfo) -> Self {
+        Self {
+            size: game_info.size,
+            mine_count: game_info.mine_count,
+            visibility: game_info.visibility,
+            invite_code: game_info.invite_code.clone(),
+            seed: game_info.seed,
+            first_click: game_info.first_click,
+            actions: game_info.actions.clone(),
+            players: game_info.players.clone(),
+            owner: game_info.owner.clone(),
+        }
+    }
+}
+
+impl PersistedGame {
+    fn into_game_info(self) -> GameInfo {
+        let game = self.first_click.map(|(x, y)| {
+            let mut game = Minesweeper::new_with_first_click(self.size, self.mine_count, (x, y));
+            for (kind, x, y) in &self.actions {
+                match kind {
+                    ActionKind::Click => {
+                        let _ = game.click_tile(*x, *y);
+                    }
+                    ActionKind::Flag => {
+                        let _ = game.toggle_flag(*x, *y);
+                    }
+                }
+            }
+            game
+        });
+
+        let (updates, _) = broadcast::channel(BOARD_UPDATES_CAPACITY);
+        GameInfo {
+            first_click_made: self.first_click.is_some(),
+            game,
+            size: self.size,
+            mine_count: self.mine_count,
+            updates,
+            players: self.players,
+            visibility: self.visibility,
+            invite_code: self.invite_code,
+            seed: self.seed,
+            first_click: self.first_click,
+            actions: self.actions,
+            owner: self.owner,
+            last_touched: Instant::now(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum ActionKind {
+    Click,
+    Flag,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Player {
+    player_id: String,
+    name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Visibility {
+    Public,
+    Private,
 }
 
 #[derive(Serialize, Deserialize)]
 struct NewGameRequest {
     size: usize,
     mine_count: usize,
+    #[serde(default)]
+    visibility: Option<Visibility>,
+    #[serde(default)]
+    seed: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct JoinRequest {
+    name: String,
+    // Required to join a private game; ignored for public ones.
+    #[serde(default)]
+    invite_code: Option<String>,
+}
+
+/// Query string carried by click/flag requests so an anonymous joiner (who
+/// has no OIDC subject) can present the `player_id` they were issued by
+/// `join_game`.
+#[derive(Deserialize)]
+struct ActorQuery {
+    #[serde(default)]
+    player_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct LobbyEntry {
+    game_id: String,
+    size: usize,
+    mine_count: usize,
+    player_count: usize,
+    game_state: String,
+}
+
+#[derive(Serialize)]
+struct MyGameEntry {
+    game_id: String,
+    size: usize,
+    mine_count: usize,
+    game_state: String,
+    // Only the owner sees this (this endpoint is OIDC-gated), so it's safe
+    // to hand back for sharing with whoever the owner wants to invite.
+    invite_code: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ReplayResponse {
+    seed: u64,
+    first_click: Option<(usize, usize)>,
+    actions: Vec<(ActionKind, usize, usize)>,
+}
+
+#[derive(Deserialize)]
+struct ReplayRequest {
+    size: usize,
+    mine_count: usize,
+    seed: u64,
+    first_click: (usize, usize),
+    actions: Vec<(ActionKind, usize, usize)>,
 }
 
 #[derive(Serialize)]
@@ -49,7 +369,7 @@ struct TileResponse {
     value: Option<String>, // "bomb", number as string, or None if not exposed
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 struct ActionResponse {
     success: bool,
     message: String,
@@ -59,18 +379,82 @@ struct ActionResponse {
 
 #[tokio::main]
 async fn main() {
-    let games: GameStorage = Arc::new(Mutex::new(HashMap::new()));
+    let use_file_store = std::env::var("GAME_STORE").unwrap_or_else(|_| "memory".to_string()) == "file";
+    let games: GameStorage = if use_file_store {
+        let path = std::env::var("GAME_STORE_PATH").unwrap_or_else(|_| "games.json".to_string());
+        Arc::new(FileGameStore::new(PathBuf::from(path)))
+    } else {
+        Arc::new(InMemoryGameStore::default())
+    };
+
+    let ttl_secs: u64 = std::env::var("GAME_TTL_SECS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(3600);
+    let eviction_games = games.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            eviction_games.evict_idle(Duration::from_secs(ttl_secs));
+        }
+    });
 
     let app = Router::new()
         .route("/", get(serve_index))
+        .route("/game/{game_id}", get(serve_game_page))
         .route("/api/new-game", post(new_game))
+        .route("/api/replay", post(replay_game))
+        .route("/api/lobby", get(list_lobby))
+        .route("/api/my-games", get(my_games))
         .route("/api/game/{game_id}", get(get_game_state))
+        .route("/api/game/{game_id}/join", post(join_game))
+        .route("/api/game/{game_id}/replay", get(get_replay))
+        .route("/api/game/{game_id}/qr", get(game_qr))
         .route("/api/game/{game_id}/click/{x}/{y}", post(click_tile))
         .route("/api/game/{game_id}/flag/{x}/{y}", post(toggle_flag))
+        .route("/api/game/{game_id}/ws", get(game_ws))
         .nest_service("/static", ServeDir::new("static"))
         .layer(CorsLayer::permissive())
         .with_state(games);
 
+    // Check if we should gate game ownership behind OIDC login
+    let use_oidc = std::env::var("USE_OIDC").unwrap_or_else(|_| "false".to_string()) == "true";
+
+    let app = if use_oidc {
+        let app_url = std::env::var("APP_URL").expect("APP_URL must be set when USE_OIDC=true");
+        let issuer = std::env::var("OIDC_ISSUER").expect("OIDC_ISSUER must be set when USE_OIDC=true");
+        let client_id = std::env::var("OIDC_CLIENT_ID").expect("OIDC_CLIENT_ID must be set when USE_OIDC=true");
+        let client_secret = std::env::var("OIDC_CLIENT_SECRET").ok();
+
+        let oidc_client = OidcClient::<EmptyAdditionalClaims>::builder()
+            .with_default_http_client()
+            .with_redirect_url(format!("{app_url}/auth/callback").parse().unwrap())
+            .with_client_id(client_id)
+            .with_client_secret(client_secret.unwrap_or_default())
+            .add_scope("profile")
+            .discover(issuer)
+            .await
+            .expect("failed to discover OIDC issuer")
+            .build();
+
+        let session_layer = SessionManagerLayer::new(MemoryStore::default());
+        let oidc_auth_layer = OidcAuthLayer::<EmptyAdditionalClaims>::new(oidc_client);
+
+        app.route("/auth/login", get(login))
+            .layer(OidcLoginLayer::<EmptyAdditionalClaims>::new())
+            .layer(
+                ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|e: MiddlewareError| async move {
+                        e.into_response()
+                    }))
+                    .layer(oidc_auth_layer),
+            )
+            .layer(session_layer)
+    } else {
+        app
+    };
+
     // Check if we should use HTTPS
     let use_https = std::env::var("USE_HTTPS").unwrap_or_else(|_| "false".to_string()) == "true";
     
@@ -101,130 +485,105 @@ async fn main() {
     }
 }
 
+// Difficulty presets offered on the landing page: (name, size, mine_count).
+const DIFFICULTY_PRESETS: &[(&str, usize, usize)] = &[
+    ("Beginner", 9, 10),
+    ("Intermediate", 16, 40),
+    ("Expert", 24, 99),
+];
+
+struct DifficultyPreset {
+    name: &'static str,
+    size: usize,
+    mine_count: usize,
+}
+
+#[derive(TemplateOnce)]
+#[template(path = "board.stpl")]
+struct BoardTemplate {
+    game: GameResponse,
+    presets: Vec<DifficultyPreset>,
+}
+
+fn render_board_page(game: GameResponse) -> Html<String> {
+    let template = BoardTemplate {
+        game,
+        presets: DIFFICULTY_PRESETS
+            .iter()
+            .map(|&(name, size, mine_count)| DifficultyPreset {
+                name,
+                size,
+                mine_count,
+            })
+            .collect(),
+    };
+
+    Html(
+        template
+            .render_once()
+            .expect("board template failed to render"),
+    )
+}
+
 async fn serve_index() -> Html<String> {
-    let html = tokio::fs::read_to_string("static/index.html")
-        .await
-        .unwrap_or_else(|_| {
-            r#"
-<!DOCTYPE html>
-<html>
-<head>
-    <title>Minesweeper</title>
-    <style>
-        body { font-family: Arial, sans-serif; margin: 20px; }
-        .board { display: inline-block; border: 2px solid #333; }
-        .row { display: flex; }
-        .tile { 
-            width: 30px; height: 30px; 
-            border: 1px solid #999; 
-            display: flex; align-items: center; justify-content: center;
-            cursor: pointer; font-weight: bold;
-            background: #ddd;
-        }
-        .tile.exposed { background: #fff; }
-        .tile.flagged { background: #ff9; }
-        .tile.bomb { background: #f66; }
-        .controls { margin: 20px 0; }
-        button { padding: 10px 20px; margin: 5px; }
-    </style>
-</head>
-<body>
-    <h1>Minesweeper</h1>
-    <div class="controls">
-        <button onclick="newGame()">New Game</button>
-        <span id="game-status">Ready to play!</span>
-    </div>
-    <div id="board"></div>
-    <script>
-        let currentGameId = null;
-        
-        async function newGame() {
-            const response = await fetch('/api/new-game', {
-                method: 'POST',
-                headers: { 'Content-Type': 'application/json' },
-                body: JSON.stringify({ size: 10, mine_count: 15 })
-            });
-            const game = await response.json();
-            currentGameId = game.game_id;
-            renderBoard(game);
-            document.getElementById('game-status').textContent = 'Game ready - click any tile to start!';
-        }
-        
-        async function clickTile(x, y) {
-            if (!currentGameId) return;
-            const response = await fetch(`/api/game/${currentGameId}/click/${x}/${y}`, { method: 'POST' });
-            const result = await response.json();
-            renderBoard(result);
-            document.getElementById('game-status').textContent = result.game_state;
-        }
-        
-        async function flagTile(x, y) {
-            if (!currentGameId) return;
-            const response = await fetch(`/api/game/${currentGameId}/flag/${x}/${y}`, { method: 'POST' });
-            const result = await response.json();
-            renderBoard(result);
-        }
-        
-        function renderBoard(game) {
-            const board = document.getElementById('board');
-            board.innerHTML = '';
-            board.className = 'board';
-            
-            game.board.forEach((row, x) => {
-                const rowDiv = document.createElement('div');
-                rowDiv.className = 'row';
-                
-                row.forEach((tile, y) => {
-                    const tileDiv = document.createElement('div');
-                    tileDiv.className = 'tile';
-                    
-                    if (tile.exposed) {
-                        tileDiv.classList.add('exposed');
-                        if (tile.value === 'bomb') {
-                            tileDiv.classList.add('bomb');
-                            tileDiv.textContent = '💣';
-                        } else if (tile.value && tile.value !== '0') {
-                            tileDiv.textContent = tile.value;
-                        }
-                    } else if (tile.flagged) {
-                        tileDiv.classList.add('flagged');
-                        tileDiv.textContent = '🚩';
-                    }
-                    
-                    tileDiv.onclick = () => clickTile(x, y);
-                    tileDiv.oncontextmenu = (e) => { e.preventDefault(); flagTile(x, y); };
-                    
-                    rowDiv.appendChild(tileDiv);
-                });
-                
-                board.appendChild(rowDiv);
-            });
-        }
-        
-        // Start with a new game
-        newGame();
-    </script>
-</body>
-</html>
-            "#.to_string()
-        });
-    Html(html)
+    let (_, size, mine_count) = DIFFICULTY_PRESETS[0];
+
+    render_board_page(GameResponse {
+        game_id: String::new(),
+        size,
+        mine_count,
+        game_state: "InProgress".to_string(),
+        board: create_empty_board_response(size),
+    })
+}
+
+async fn serve_game_page(
+    State(games): State<GameStorage>,
+    Path(game_id): Path<String>,
+) -> Result<Html<String>, StatusCode> {
+    let game_info = games.get(&game_id).ok_or(StatusCode::NOT_FOUND)?;
+
+    let (game_state, board) = if let Some(ref game) = game_info.game {
+        (format!("{:?}", game.get_game_state()), serialize_board(game))
+    } else {
+        ("InProgress".to_string(), create_empty_board_response(game_info.size))
+    };
+
+    Ok(render_board_page(GameResponse {
+        game_id,
+        size: game_info.size,
+        mine_count: game_info.mine_count,
+        game_state,
+        board,
+    }))
 }
 
 async fn new_game(
     State(games): State<GameStorage>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
     Json(req): Json<NewGameRequest>,
 ) -> Result<Json<GameResponse>, StatusCode> {
     let game_id = generate_game_id();
-    
+    let visibility = req.visibility.unwrap_or(Visibility::Public);
+
     // Create a placeholder game info - the actual game will be created on first click
+    let (updates, _) = broadcast::channel(BOARD_UPDATES_CAPACITY);
     let game_info = GameInfo {
         game: None,
         size: req.size,
         mine_count: req.mine_count,
         first_click_made: false,
+        updates,
+        players: Vec::new(),
+        visibility,
+        invite_code: (visibility == Visibility::Private).then(|| generate_id(12)),
+        seed: req.seed.unwrap_or_else(|| rand::thread_rng().gen()),
+        first_click: None,
+        actions: Vec::new(),
+        owner: claims.map(|c| c.subject().to_string()),
+        last_touched: Instant::now(),
     };
-    
+
     let response = GameResponse {
         game_id: game_id.clone(),
         size: req.size,
@@ -232,8 +591,8 @@ async fn new_game(
         game_state: "InProgress".to_string(),
         board: create_empty_board_response(req.size),
     };
-    
-    games.lock().unwrap().insert(game_id, game_info);
+
+    games.insert(game_id, game_info);
     Ok(Json(response))
 }
 
@@ -241,15 +600,14 @@ async fn get_game_state(
     State(games): State<GameStorage>,
     Path(game_id): Path<String>,
 ) -> Result<Json<GameResponse>, StatusCode> {
-    let games = games.lock().unwrap();
     let game_info = games.get(&game_id).ok_or(StatusCode::NOT_FOUND)?;
-    
+
     let (game_state, board) = if let Some(ref game) = game_info.game {
         (format!("{:?}", game.get_game_state()), serialize_board(game))
     } else {
         ("InProgress".to_string(), create_empty_board_response(game_info.size))
     };
-    
+
     let response = GameResponse {
         game_id,
         size: game_info.size,
@@ -257,81 +615,356 @@ async fn get_game_state(
         game_state,
         board,
     };
-    
+
     Ok(Json(response))
 }
 
+async fn game_qr(
+    State(games): State<GameStorage>,
+    Path(game_id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    games.get(&game_id).ok_or(StatusCode::NOT_FOUND)?;
+
+    let app_url = std::env::var("APP_URL").unwrap_or_else(|_| "http://localhost:3000".to_string());
+    let share_url = format!("{app_url}/game/{game_id}");
+
+    let code = QrCode::new(share_url.as_bytes()).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let image = code
+        .render()
+        .min_dimensions(200, 200)
+        .dark_color(svg::Color("#000000"))
+        .light_color(svg::Color("#ffffff"))
+        .build();
+
+    Ok(([(CONTENT_TYPE, "image/svg+xml")], image))
+}
+
+/// Layered with `OidcLoginLayer`, so simply reaching this handler means the
+/// OIDC round-trip already completed and a session exists.
+async fn login() -> Redirect {
+    Redirect::to("/")
+}
+
+async fn my_games(
+    State(games): State<GameStorage>,
+    claims: OidcClaims<EmptyAdditionalClaims>,
+) -> Json<Vec<MyGameEntry>> {
+    let subject = claims.subject().as_str();
+
+    let entries = games
+        .list()
+        .into_iter()
+        .filter(|(_, game_info)| game_info.owner.as_deref() == Some(subject))
+        .map(|(game_id, game_info)| {
+            let game_state = game_info
+                .game
+                .as_ref()
+                .map(|game| format!("{:?}", game.get_game_state()))
+                .unwrap_or_else(|| "InProgress".to_string());
+
+            MyGameEntry {
+                game_id,
+                size: game_info.size,
+                mine_count: game_info.mine_count,
+                game_state,
+                invite_code: game_info.invite_code,
+            }
+        })
+        .collect();
+
+    Json(entries)
+}
+
+async fn list_lobby(State(games): State<GameStorage>) -> Json<Vec<LobbyEntry>> {
+    let mut entries: Vec<LobbyEntry> = games
+        .list()
+        .into_iter()
+        .filter(|(_, game_info)| game_info.visibility == Visibility::Public)
+        .map(|(game_id, game_info)| {
+            let game_state = game_info
+                .game
+                .as_ref()
+                .map(|game| format!("{:?}", game.get_game_state()))
+                .unwrap_or_else(|| "InProgress".to_string());
+
+            LobbyEntry {
+                game_id,
+                size: game_info.size,
+                mine_count: game_info.mine_count,
+                player_count: game_info.players.len(),
+                game_state,
+            }
+        })
+        .collect();
+
+    entries.sort_by(|a, b| a.game_id.cmp(&b.game_id));
+    Json(entries)
+}
+
+async fn join_game(
+    State(games): State<GameStorage>,
+    Path(game_id): Path<String>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    Json(req): Json<JoinRequest>,
+) -> Result<Json<Player>, StatusCode> {
+    let existing = games.get(&game_id).ok_or(StatusCode::NOT_FOUND)?;
+    if existing.visibility == Visibility::Private && existing.invite_code != req.invite_code {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let player = Player {
+        player_id: claims
+            .map(|c| c.subject().to_string())
+            .unwrap_or_else(|| generate_id(8)),
+        name: req.name,
+    };
+
+    let joined = player.clone();
+    let found = games.update(
+        &game_id,
+        Box::new(move |game_info| game_info.players.push(joined)),
+    );
+    if !found {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(Json(player))
+}
+
+/// True if the caller is allowed to click/flag tiles in `game_info`: games
+/// created anonymously (no owner) stay open to anyone, otherwise only the
+/// owner or a player who has joined the game's lobby may act. Authenticated
+/// callers are identified by their OIDC `subject`; anonymous joiners have no
+/// subject and are identified instead by the `player_id` they were issued
+/// when they joined (see `join_game`), which the client must present.
+fn can_act(game_info: &GameInfo, subject: Option<&str>, player_id: Option<&str>) -> bool {
+    match &game_info.owner {
+        None => true,
+        Some(owner) => {
+            if subject.is_some_and(|s| s == owner) {
+                return true;
+            }
+            subject
+                .or(player_id)
+                .is_some_and(|id| game_info.players.iter().any(|p| p.player_id == id))
+        }
+    }
+}
+
 async fn click_tile(
     State(games): State<GameStorage>,
     Path((game_id, x, y)): Path<(String, usize, usize)>,
+    Query(actor): Query<ActorQuery>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
 ) -> Result<Json<ActionResponse>, StatusCode> {
-    let mut games = games.lock().unwrap();
-    let game_info = games.get_mut(&game_id).ok_or(StatusCode::NOT_FOUND)?;
-    
-    // If this is the first click, create the game now
-    if !game_info.first_click_made {
-        game_info.game = Some(Minesweeper::new_with_first_click(
-            game_info.size,
-            game_info.mine_count,
-            (x, y),
-        ));
-        game_info.first_click_made = true;
-        
-        // The first click is already processed by new_with_first_click
-        let game = game_info.game.as_ref().unwrap();
-        let response = ActionResponse {
-            success: true,
-            message: "First click processed! Game board generated.".to_string(),
-            game_state: format!("{:?}", game.get_game_state()),
-            board: serialize_board(game),
-        };
-        
-        return Ok(Json(response));
+    let existing = games.get(&game_id).ok_or(StatusCode::NOT_FOUND)?;
+    let subject = claims.as_ref().map(|c| c.subject().as_str());
+    if !can_act(&existing, subject, actor.player_id.as_deref()) {
+        return Err(StatusCode::FORBIDDEN);
     }
-    
-    // Normal click processing for subsequent clicks
-    let game = game_info.game.as_mut().ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
-    let result = game.click_tile(x, y);
-    
-    let response = ActionResponse {
-        success: result.is_ok(),
-        message: result.err().unwrap_or_else(|| "Success".to_string()),
-        game_state: format!("{:?}", game.get_game_state()),
-        board: serialize_board(game),
-    };
-    
-    Ok(Json(response))
+
+    let mut response = None;
+    games.update(
+        &game_id,
+        Box::new(|game_info| {
+            // If this is the first click, create the game now
+            if !game_info.first_click_made {
+                game_info.game = Some(Minesweeper::new_with_first_click(
+                    game_info.size,
+                    game_info.mine_count,
+                    (x, y),
+                ));
+                game_info.first_click_made = true;
+                game_info.first_click = Some((x, y));
+
+                // The first click is already processed by new_with_first_click
+                let game = game_info.game.as_ref().unwrap();
+                let r = ActionResponse {
+                    success: true,
+                    message: "First click processed! Game board generated.".to_string(),
+                    game_state: format!("{:?}", game.get_game_state()),
+                    board: serialize_board(game),
+                };
+
+                let _ = game_info.updates.send(r.clone());
+                response = Some(r);
+                return;
+            }
+
+            // Normal click processing for subsequent clicks
+            let Some(game) = game_info.game.as_mut() else {
+                return;
+            };
+            let result = game.click_tile(x, y);
+
+            let r = ActionResponse {
+                success: result.is_ok(),
+                message: result.err().unwrap_or_else(|| "Success".to_string()),
+                game_state: format!("{:?}", game.get_game_state()),
+                board: serialize_board(game),
+            };
+
+            game_info.actions.push((ActionKind::Click, x, y));
+            let _ = game_info.updates.send(r.clone());
+            response = Some(r);
+        }),
+    );
+
+    response.map(Json).ok_or(StatusCode::INTERNAL_SERVER_ERROR)
 }
 
 async fn toggle_flag(
     State(games): State<GameStorage>,
     Path((game_id, x, y)): Path<(String, usize, usize)>,
+    Query(actor): Query<ActorQuery>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
 ) -> Result<Json<ActionResponse>, StatusCode> {
-    let mut games = games.lock().unwrap();
-    let game_info = games.get_mut(&game_id).ok_or(StatusCode::NOT_FOUND)?;
-    
-    // Can't flag before first click
-    if !game_info.first_click_made {
-        let response = ActionResponse {
-            success: false,
-            message: "Make your first click before flagging!".to_string(),
-            game_state: "InProgress".to_string(),
-            board: create_empty_board_response(game_info.size),
-        };
-        return Ok(Json(response));
+    let existing = games.get(&game_id).ok_or(StatusCode::NOT_FOUND)?;
+    let subject = claims.as_ref().map(|c| c.subject().as_str());
+    if !can_act(&existing, subject, actor.player_id.as_deref()) {
+        return Err(StatusCode::FORBIDDEN);
     }
-    
-    let game = game_info.game.as_mut().ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
-    let result = game.toggle_flag(x, y);
-    
-    let response = ActionResponse {
-        success: result.is_ok(),
-        message: result.err().unwrap_or_else(|| "Success".to_string()),
-        game_state: format!("{:?}", game.get_game_state()),
-        board: serialize_board(game),
+
+    let mut response = None;
+    games.update(
+        &game_id,
+        Box::new(|game_info| {
+            // Can't flag before first click
+            if !game_info.first_click_made {
+                response = Some(ActionResponse {
+                    success: false,
+                    message: "Make your first click before flagging!".to_string(),
+                    game_state: "InProgress".to_string(),
+                    board: create_empty_board_response(game_info.size),
+                });
+                return;
+            }
+
+            let Some(game) = game_info.game.as_mut() else {
+                return;
+            };
+            let result = game.toggle_flag(x, y);
+
+            let r = ActionResponse {
+                success: result.is_ok(),
+                message: result.err().unwrap_or_else(|| "Success".to_string()),
+                game_state: format!("{:?}", game.get_game_state()),
+                board: serialize_board(game),
+            };
+
+            game_info.actions.push((ActionKind::Flag, x, y));
+            let _ = game_info.updates.send(r.clone());
+            response = Some(r);
+        }),
+    );
+
+    response.map(Json).ok_or(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn get_replay(
+    State(games): State<GameStorage>,
+    Path(game_id): Path<String>,
+) -> Result<Json<ReplayResponse>, StatusCode> {
+    let game_info = games.get(&game_id).ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(ReplayResponse {
+        seed: game_info.seed,
+        first_click: game_info.first_click,
+        actions: game_info.actions,
+    }))
+}
+
+async fn replay_game(
+    State(games): State<GameStorage>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    Json(req): Json<ReplayRequest>,
+) -> Result<Json<GameResponse>, StatusCode> {
+    let (fx, fy) = req.first_click;
+    let mut game = Minesweeper::new_with_first_click(req.size, req.mine_count, (fx, fy));
+
+    for (kind, x, y) in &req.actions {
+        match kind {
+            ActionKind::Click => {
+                let _ = game.click_tile(*x, *y);
+            }
+            ActionKind::Flag => {
+                let _ = game.toggle_flag(*x, *y);
+            }
+        }
+    }
+
+    let game_id = generate_game_id();
+    let game_state = format!("{:?}", game.get_game_state());
+    let board = serialize_board(&game);
+
+    let (updates, _) = broadcast::channel(BOARD_UPDATES_CAPACITY);
+    let game_info = GameInfo {
+        game: Some(game),
+        size: req.size,
+        mine_count: req.mine_count,
+        first_click_made: true,
+        updates,
+        players: Vec::new(),
+        visibility: Visibility::Public,
+        invite_code: None,
+        seed: req.seed,
+        first_click: Some(req.first_click),
+        actions: req.actions,
+        owner: claims.map(|c| c.subject().to_string()),
+        last_touched: Instant::now(),
     };
-    
-    Ok(Json(response))
+    games.insert(game_id.clone(), game_info);
+
+    Ok(Json(GameResponse {
+        game_id,
+        size: req.size,
+        mine_count: req.mine_count,
+        game_state,
+        board,
+    }))
+}
+
+async fn game_ws(
+    State(games): State<GameStorage>,
+    Path(game_id): Path<String>,
+    ws: WebSocketUpgrade,
+) -> Result<impl IntoResponse, StatusCode> {
+    let game_info = games.get(&game_id).ok_or(StatusCode::NOT_FOUND)?;
+    let rx = game_info.updates.subscribe();
+
+    Ok(ws.on_upgrade(move |socket| forward_board_updates(socket, rx)))
+}
+
+async fn forward_board_updates(socket: WebSocket, mut rx: broadcast::Receiver<ActionResponse>) {
+    let (mut sender, mut receiver) = socket.split();
+
+    // Spectators don't send anything meaningful; just watch for the socket
+    // closing so we can stop forwarding updates.
+    let mut recv_task = tokio::spawn(async move {
+        while let Some(Ok(_)) = receiver.next().await {}
+    });
+
+    let mut send_task = tokio::spawn(async move {
+        loop {
+            match rx.recv().await {
+                Ok(update) => {
+                    let Ok(text) = serde_json::to_string(&update) else {
+                        continue;
+                    };
+                    if sender.send(Message::Text(text.into())).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    tokio::select! {
+        _ = &mut recv_task => send_task.abort(),
+        _ = &mut send_task => recv_task.abort(),
+    }
 }
 
 fn serialize_board(game: &Minesweeper) -> Vec<Vec<TileResponse>> {
@@ -382,10 +1015,14 @@ fn create_empty_board_response(size: usize) -> Vec<Vec<TileResponse>> {
 }
 
 fn generate_game_id() -> String {
+    generate_id(8)
+}
+
+fn generate_id(len: usize) -> String {
     use rand::distributions::Alphanumeric;
     rand::thread_rng()
         .sample_iter(&Alphanumeric)
-        .take(8)
+        .take(len)
         .map(char::from)
         .collect()
 }